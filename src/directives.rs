@@ -0,0 +1,126 @@
+use log::{Level, LevelFilter};
+
+/// A single per-target level override, e.g. `("hyper", LevelFilter::Warn)`
+pub type LevelDirective = (&'static str, LevelFilter);
+
+/// Per-target level overrides consulted by [`crate::sink::LokiSink::enabled`]
+///
+/// A record is matched against the directive whose target is the *longest* matching prefix of
+/// the record's target, so a directive for `"hyper::client"` takes precedence over one for
+/// `"hyper"` when logging from `hyper::client::pool`. A record whose target matches no directive
+/// is allowed through unfiltered (the crate-wide level passed to `log::set_max_level` is still
+/// the outer filter in that case).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LevelDirectives {
+	// sorted by target length, descending, so the first prefix match found is the most specific
+	directives: Vec<(String, LevelFilter)>,
+}
+
+impl LevelDirectives {
+	pub(crate) fn new(directives: Vec<LevelDirective>) -> Self {
+		Self::from_pairs(directives.into_iter().map(|(target, level)| (String::from(target), level)).collect())
+	}
+
+	/// Parses an `RUST_LOG`-style directive string, e.g. `"info,hyper=warn,myapp::db=debug"`.
+	/// A bare level with no `target=` prefix sets the default for any target that doesn't match
+	/// a more specific directive. Entries that fail to parse are ignored.
+	pub(crate) fn parse(spec: &str) -> Self {
+		let directives = spec
+			.split(',')
+			.map(str::trim)
+			.filter(|part| !part.is_empty())
+			.filter_map(|part| match part.split_once('=') {
+				Some((target, level)) => Some((String::from(target), level.parse().ok()?)),
+				None => Some((String::new(), part.parse().ok()?)),
+			})
+			.collect();
+
+		Self::from_pairs(directives)
+	}
+
+	fn from_pairs(mut directives: Vec<(String, LevelFilter)>) -> Self {
+		directives.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+		Self { directives }
+	}
+
+	/// Whether a record logged at `level` from `target` should be accepted
+	pub(crate) fn enabled(&self, target: &str, level: Level) -> bool {
+		self.directives
+			.iter()
+			.find(|(prefix, _)| matches_module_path(prefix, target))
+			.map_or(true, |(_, max_level)| level <= *max_level)
+	}
+}
+
+/// Whether `target` is `prefix` itself or a descendant of it at a `::` module boundary, e.g.
+/// `"myapp::db"` matches targets `"myapp::db"` and `"myapp::db::pool"` but not the sibling
+/// `"myapp::database"`. The empty prefix (the bare-level default directive) matches every target.
+fn matches_module_path(prefix: &str, target: &str) -> bool {
+	if prefix.is_empty() {
+		return true;
+	}
+
+	match target.strip_prefix(prefix) {
+		Some(rest) => rest.is_empty() || rest.starts_with("::"),
+		None => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use log::LevelFilter;
+
+	fn directives(pairs: &[(&'static str, LevelFilter)]) -> LevelDirectives {
+		LevelDirectives::new(pairs.to_vec())
+	}
+
+	#[test]
+	fn no_directives_allows_everything() {
+		let d = LevelDirectives::default();
+		assert!(d.enabled("anything", Level::Trace));
+	}
+
+	#[test]
+	fn bare_level_sets_the_default_for_unmatched_targets() {
+		let d = LevelDirectives::parse("warn");
+		assert!(d.enabled("myapp", Level::Warn));
+		assert!(!d.enabled("myapp", Level::Info));
+	}
+
+	#[test]
+	fn longest_matching_prefix_wins() {
+		let d = directives(&[("hyper", LevelFilter::Warn), ("hyper::client", LevelFilter::Debug)]);
+		assert!(d.enabled("hyper::client::pool", Level::Debug));
+		assert!(!d.enabled("hyper::connect", Level::Debug));
+	}
+
+	#[test]
+	fn directive_does_not_leak_across_sibling_modules() {
+		let d = directives(&[("myapp::db", LevelFilter::Warn)]);
+		assert!(d.enabled("myapp::database", Level::Debug));
+		assert!(d.enabled("myapp::database", Level::Info));
+	}
+
+	#[test]
+	fn directive_does_not_leak_across_sibling_crates_sharing_a_prefix() {
+		let d = directives(&[("hyper", LevelFilter::Warn)]);
+		assert!(d.enabled("hyperion::engine", Level::Debug));
+	}
+
+	#[test]
+	fn directive_matches_its_own_target_exactly() {
+		let d = directives(&[("myapp::db", LevelFilter::Warn)]);
+		assert!(d.enabled("myapp::db", Level::Warn));
+		assert!(!d.enabled("myapp::db", Level::Info));
+	}
+
+	#[test]
+	fn parse_ignores_unparseable_entries() {
+		let d = LevelDirectives::parse("info,hyper=warn,myapp::db=debug,garbage=nope");
+		assert!(d.enabled("hyper", Level::Warn));
+		assert!(d.enabled("myapp::db", Level::Debug));
+		assert!(d.enabled("other", Level::Info));
+		assert!(!d.enabled("other", Level::Debug));
+	}
+}
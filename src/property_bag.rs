@@ -1,26 +1,38 @@
 use once_cell::sync::Lazy;
 use serde::Serialize;
-use std::{collections::HashMap, sync::RwLock};
+use std::{cell::RefCell, collections::HashMap, sync::RwLock};
 
 /// Property bag
 pub static PROPERTY_BAG: Lazy<PropertyBag> = Lazy::new(PropertyBag::new);
-type PropertyStack = RwLock<HashMap<String, serde_json::Value>>;
 
-/// Property bag that hold the `PropertyStack` used to enrich the logs written to loki
+pub(crate) type PropertyMap = HashMap<String, serde_json::Value>;
+
+thread_local! {
+	/// Per-thread property stack. Keeping this thread-local means a property pushed while
+	/// handling one request (e.g. a `CorrelationId`) can never bleed into a log line emitted by
+	/// an unrelated request running concurrently on another thread.
+	static LOCAL_PROPS: RefCell<PropertyMap> = RefCell::new(HashMap::new());
+}
+
+/// Property bag that holds the properties used to enrich the logs written to loki
+///
+/// Properties pushed with [`PropertyBag::push`] are only visible on the thread that pushed them.
+/// Properties pushed with [`PropertyBag::push_global`] are shared across every thread, which is
+/// only appropriate for genuinely process-wide values (e.g. `InstanceId`).
 #[derive(Debug)]
 pub struct PropertyBag {
-	props: PropertyStack,
+	global: RwLock<PropertyMap>,
 }
 
 impl PropertyBag {
 	/// Initializes a new `PropertyBag`
 	fn new() -> Self {
 		PropertyBag {
-			props: RwLock::new(HashMap::new()),
+			global: RwLock::new(HashMap::new()),
 		}
 	}
 
-	/// Pushes a new label and its corresponding data onto the `PropertyStack` in the `PropertyBag`
+	/// Pushes a new property onto the calling thread's local property stack
 	///
 	/// ```rust
 	/// // keep the guard around otherwise the property will be dropped immediately
@@ -28,30 +40,64 @@ impl PropertyBag {
 	/// let _guard = loki_sink::PROPERTY_BAG.push("LabelName", &"LabelValue/Object");
 	/// ```
 	pub fn push<T: Serialize>(&self, name: &str, object: &T) -> PropertyStackGuard {
-		let t = serde_json::to_value(object).unwrap();
-		self.props.write().unwrap().insert(String::from(name), t);
+		let value = serde_json::to_value(object).unwrap();
+		LOCAL_PROPS.with(|props| props.borrow_mut().insert(String::from(name), value));
 
 		PropertyStackGuard {
 			key: String::from(name),
-			props: &self.props,
+			scope: PropertyScope::Local,
 		}
 	}
 
-	/// Serializes the properties in the `PropertyStack` as json
-	pub(crate) fn as_json(&self) -> String {
-		serde_json::to_string(&self.props.read().unwrap().clone()).unwrap()
+	/// Pushes a new property that is shared across every thread, for process-wide values like
+	/// `InstanceId` that aren't scoped to a single request or task
+	///
+	/// ```rust
+	/// let _guard = loki_sink::PROPERTY_BAG.push_global("InstanceId", &"worker-1");
+	/// ```
+	pub fn push_global<T: Serialize>(&self, name: &str, object: &T) -> PropertyStackGuard {
+		let value = serde_json::to_value(object).unwrap();
+		self.global.write().unwrap().insert(String::from(name), value);
+
+		PropertyStackGuard {
+			key: String::from(name),
+			scope: PropertyScope::Global,
+		}
+	}
+
+	/// Returns the calling thread's local properties, merged with the global properties
+	///
+	/// Local properties take precedence over global ones of the same name.
+	pub(crate) fn snapshot(&self) -> PropertyMap {
+		let mut merged = self.global.read().unwrap().clone();
+		LOCAL_PROPS.with(|props| merged.extend(props.borrow().clone()));
+
+		merged
 	}
 }
 
+/// Which property stack a [`PropertyStackGuard`] needs to clean up from on drop
+enum PropertyScope {
+	Local,
+	Global,
+}
+
 /// Guard object with a drop implementation that will remove the guarded property
-/// from the label stack at the end of the scope
-pub struct PropertyStackGuard<'a> {
+/// from the property bag at the end of the scope
+pub struct PropertyStackGuard {
 	key: String,
-	props: &'a PropertyStack,
+	scope: PropertyScope,
 }
 
-impl Drop for PropertyStackGuard<'_> {
+impl Drop for PropertyStackGuard {
 	fn drop(&mut self) {
-		self.props.write().unwrap().remove(&self.key);
+		match self.scope {
+			PropertyScope::Local => {
+				LOCAL_PROPS.with(|props| props.borrow_mut().remove(&self.key));
+			}
+			PropertyScope::Global => {
+				PROPERTY_BAG.global.write().unwrap().remove(&self.key);
+			}
+		}
 	}
 }
@@ -0,0 +1,249 @@
+//! Minimal hand-rolled protobuf encoder for Loki's `PushRequest` wire format.
+//!
+//! Loki's native push endpoint only ever needs these three message shapes, so pulling in a full
+//! prost/build.rs + `.proto` pipeline for them would be overkill; these helpers speak just enough
+//! of the protobuf wire format (<https://protobuf.dev/programming-guides/encoding/>) to build
+//! them directly:
+//!
+//! ```text
+//! message PushRequest { repeated StreamAdapter streams = 1; }
+//! message StreamAdapter { string labels = 1; repeated EntryAdapter entries = 2; }
+//! message EntryAdapter { google.protobuf.Timestamp timestamp = 1; string line = 2; }
+//! ```
+
+use crate::sink::LokiStream;
+use std::collections::HashMap;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_LEN: u8 = 2;
+
+fn write_varint(mut value: u64, buf: &mut Vec<u8>) {
+	loop {
+		let byte = (value & 0x7f) as u8;
+		value >>= 7;
+		if value == 0 {
+			buf.push(byte);
+			break;
+		}
+		buf.push(byte | 0x80);
+	}
+}
+
+fn write_tag(field_number: u32, wire_type: u8, buf: &mut Vec<u8>) {
+	write_varint(((field_number as u64) << 3) | wire_type as u64, buf);
+}
+
+fn write_varint_field(field_number: u32, value: u64, buf: &mut Vec<u8>) {
+	write_tag(field_number, WIRE_VARINT, buf);
+	write_varint(value, buf);
+}
+
+fn write_bytes_field(field_number: u32, value: &[u8], buf: &mut Vec<u8>) {
+	write_tag(field_number, WIRE_LEN, buf);
+	write_varint(value.len() as u64, buf);
+	buf.extend_from_slice(value);
+}
+
+fn write_string_field(field_number: u32, value: &str, buf: &mut Vec<u8>) {
+	write_bytes_field(field_number, value.as_bytes(), buf);
+}
+
+/// Formats a label map as the Prometheus-style string Loki expects for a stream,
+/// e.g. `{Environment="Stage",level="error"}` (pairs are sorted)
+///
+/// Label names that aren't a valid Prometheus label name (e.g. a promoted property containing
+/// spaces or punctuation) are dropped rather than emitted malformed, since Loki rejects the whole
+/// push on a single bad label. Values are escaped so a `"`, `\`, or newline in a promoted
+/// property can't break out of the quoted value and produce an invalid label set.
+pub(crate) fn format_labels(labels: &HashMap<String, String>) -> String {
+	let mut pairs: Vec<String> = labels
+		.iter()
+		.filter(|(name, _)| is_valid_label_name(name))
+		.map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+		.collect();
+	pairs.sort();
+	format!("{{{}}}", pairs.join(","))
+}
+
+/// Whether `name` is a valid Prometheus label name: ASCII letters, digits, and underscores, not
+/// starting with a digit. See <https://prometheus.io/docs/concepts/data_model/#metric-names-and-labels>
+fn is_valid_label_name(name: &str) -> bool {
+	let mut chars = name.chars();
+	match chars.next() {
+		Some(c) if c == '_' || c.is_ascii_alphabetic() => {}
+		_ => return false,
+	}
+	chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Escapes `\`, `"`, and newlines in a label value so it can be safely embedded inside a quoted
+/// Prometheus label string
+fn escape_label_value(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	for c in value.chars() {
+		match c {
+			'\\' => escaped.push_str("\\\\"),
+			'"' => escaped.push_str("\\\""),
+			'\n' => escaped.push_str("\\n"),
+			_ => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// Encodes a `google.protobuf.Timestamp` from nanoseconds since the unix epoch
+fn encode_timestamp(epoch_nanos: u128) -> Vec<u8> {
+	let seconds = (epoch_nanos / 1_000_000_000) as u64;
+	let nanos = (epoch_nanos % 1_000_000_000) as u64;
+
+	let mut buf = Vec::new();
+	write_varint_field(1, seconds, &mut buf);
+	write_varint_field(2, nanos, &mut buf);
+	buf
+}
+
+/// Encodes a single `EntryAdapter { timestamp, line }`
+fn encode_entry(epoch_nanos: u128, line: &str) -> Vec<u8> {
+	let mut buf = Vec::new();
+	write_bytes_field(1, &encode_timestamp(epoch_nanos), &mut buf);
+	write_string_field(2, line, &mut buf);
+	buf
+}
+
+/// Encodes a single `StreamAdapter { labels, entries }`
+fn encode_stream(stream: &LokiStream) -> Vec<u8> {
+	let mut buf = Vec::new();
+	write_string_field(1, &format_labels(&stream.stream), &mut buf);
+
+	for (time, line) in &stream.values {
+		let epoch_nanos: u128 = time.parse().unwrap_or(0);
+		write_bytes_field(2, &encode_entry(epoch_nanos, line), &mut buf);
+	}
+
+	buf
+}
+
+/// Encodes the top level `PushRequest { repeated streams }` that Loki's protobuf push endpoint expects
+pub(crate) fn encode_push_request(streams: &[LokiStream]) -> Vec<u8> {
+	let mut buf = Vec::new();
+	for stream in streams {
+		write_bytes_field(1, &encode_stream(stream), &mut buf);
+	}
+	buf
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Reads a single varint starting at `buf[*pos]`, advancing `pos` past it
+	fn read_varint(buf: &[u8], pos: &mut usize) -> u64 {
+		let mut value = 0u64;
+		let mut shift = 0;
+		loop {
+			let byte = buf[*pos];
+			*pos += 1;
+			value |= ((byte & 0x7f) as u64) << shift;
+			if byte & 0x80 == 0 {
+				return value;
+			}
+			shift += 7;
+		}
+	}
+
+	/// Reads a single tag + length-delimited field, returning `(field_number, bytes)`. Only
+	/// supports the wire types this module's encoder ever produces.
+	fn read_len_field<'a>(buf: &'a [u8], pos: &mut usize) -> (u32, &'a [u8]) {
+		let tag = read_varint(buf, pos);
+		assert_eq!(tag as u8 & 0x7, WIRE_LEN, "expected a length-delimited field");
+		let field_number = (tag >> 3) as u32;
+		let len = read_varint(buf, pos) as usize;
+		let bytes = &buf[*pos..*pos + len];
+		*pos += len;
+		(field_number, bytes)
+	}
+
+	fn stream(labels: &[(&str, &str)], values: &[(&str, &str)]) -> LokiStream {
+		LokiStream {
+			stream: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+			values: values.iter().map(|(t, l)| (t.to_string(), l.to_string())).collect(),
+		}
+	}
+
+	#[test]
+	fn encode_timestamp_splits_seconds_and_nanos() {
+		let buf = encode_timestamp(1_700_000_123_456_789);
+
+		let mut pos = 0;
+		let tag = read_varint(&buf, &mut pos);
+		assert_eq!(tag >> 3, 1);
+		assert_eq!(tag as u8 & 0x7, WIRE_VARINT);
+		let seconds = read_varint(&buf, &mut pos);
+		assert_eq!(seconds, 1_700_000);
+
+		let tag = read_varint(&buf, &mut pos);
+		assert_eq!(tag >> 3, 2);
+		let nanos = read_varint(&buf, &mut pos);
+		assert_eq!(nanos, 123_456_789);
+		assert_eq!(pos, buf.len());
+	}
+
+	#[test]
+	fn encode_push_request_round_trips_streams_and_entries() {
+		let streams = vec![
+			stream(&[("level", "info")], &[("1000", "first")]),
+			stream(&[("level", "error")], &[("2000", "second"), ("3000", "third")]),
+		];
+		let encoded = encode_push_request(&streams);
+
+		let mut pos = 0;
+		let mut decoded_streams = Vec::new();
+		while pos < encoded.len() {
+			let (field, stream_bytes) = read_len_field(&encoded, &mut pos);
+			assert_eq!(field, 1);
+
+			let mut spos = 0;
+			let (labels_field, labels_bytes) = read_len_field(stream_bytes, &mut spos);
+			assert_eq!(labels_field, 1);
+			let labels = String::from_utf8(labels_bytes.to_vec()).unwrap();
+
+			let mut entries = Vec::new();
+			while spos < stream_bytes.len() {
+				let (entry_field, entry_bytes) = read_len_field(stream_bytes, &mut spos);
+				assert_eq!(entry_field, 2);
+
+				let mut epos = 0;
+				let (ts_field, _) = read_len_field(entry_bytes, &mut epos);
+				assert_eq!(ts_field, 1);
+				let (line_field, line_bytes) = read_len_field(entry_bytes, &mut epos);
+				assert_eq!(line_field, 2);
+				entries.push(String::from_utf8(line_bytes.to_vec()).unwrap());
+			}
+
+			decoded_streams.push((labels, entries));
+		}
+
+		assert_eq!(decoded_streams[0].0, "{level=\"info\"}");
+		assert_eq!(decoded_streams[0].1, vec!["first"]);
+		assert_eq!(decoded_streams[1].0, "{level=\"error\"}");
+		assert_eq!(decoded_streams[1].1, vec!["second", "third"]);
+	}
+
+	#[test]
+	fn format_labels_sorts_and_quotes() {
+		let labels: HashMap<String, String> = [("b", "2"), ("a", "1")].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+		assert_eq!(format_labels(&labels), r#"{a="1",b="2"}"#);
+	}
+
+	#[test]
+	fn format_labels_escapes_quotes_backslashes_and_newlines() {
+		let labels: HashMap<String, String> = [("msg", "bad \"value\"\\with\nnewline")].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+		assert_eq!(format_labels(&labels), r#"{msg="bad \"value\"\\with\nnewline"}"#);
+	}
+
+	#[test]
+	fn format_labels_drops_invalid_label_names() {
+		let labels: HashMap<String, String> = [("valid_name", "ok"), ("not a name!", "dropped")].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+		assert_eq!(format_labels(&labels), r#"{valid_name="ok"}"#);
+	}
+}
@@ -1,14 +1,53 @@
+use crate::directives::LevelDirectives;
 use crate::{prop, PROPERTY_BAG};
+use log::kv::{Error as KvError, Key, Value, Visitor};
 use log::Log;
 use serde::Serialize;
 use std::{
 	collections::HashMap,
-	sync::RwLock,
-	time::{SystemTime, UNIX_EPOCH},
+	sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+	thread,
+	time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 pub type LokiLabels = Option<HashMap<&'static str, &'static str>>;
+/// Property names to promote from the per-log properties into dynamic Loki stream labels.
+/// See [`LokiSink`] for the cardinality warning.
+pub type PromotedLabels = Vec<&'static str>;
 const BATCH_LIMIT: usize = 1000;
+/// Above this many distinct label combinations in a single flushed batch, warn to stderr that a
+/// promoted property is likely too high-cardinality for a Loki stream label
+const HIGH_CARDINALITY_WARN_THRESHOLD: usize = 100;
+
+/// Default bound on how many log entries can be queued in memory waiting to be sent to Loki
+/// before new entries are dropped. See [`LokiSink`].
+pub(crate) const DEFAULT_QUEUE_CAPACITY: usize = 10_000;
+/// Default interval the background worker waits for more entries before flushing whatever
+/// it has, when `BATCH_LIMIT` hasn't already been hit
+pub(crate) const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Starting delay for the exponential backoff applied between retries of a failed push
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for the exponential backoff applied between retries of a failed push
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Maximum number of attempts made to push a single batch before giving up on it. Bounds how
+/// long one bad batch can hold up the worker, so it can't starve every log entry queued behind it.
+const MAX_SEND_ATTEMPTS: u32 = 8;
+
+/// Wire format used when pushing batches to Loki
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushEncoding {
+	/// The default `POST /loki/api/v1/push` JSON body
+	Json,
+	/// Loki's native protobuf `PushRequest`, snappy block-compressed, posted as
+	/// `application/x-protobuf`. Roughly halves payload size versus `Json`.
+	ProtobufSnappy,
+}
+
+impl Default for PushEncoding {
+	fn default() -> Self {
+		PushEncoding::Json
+	}
+}
 
 /// Top level request to push data to loki
 ///
@@ -38,9 +77,9 @@ struct LokiRequest {
 /// and store in like chunks (files) on the server. You want this to be as low cardinality as possible to avoid
 /// having too many individual files on the server
 #[derive(Serialize, Clone, Debug)]
-struct LokiStream {
-	stream: HashMap<String, String>,
-	values: Vec<(String, String)>,
+pub(crate) struct LokiStream {
+	pub(crate) stream: HashMap<String, String>,
+	pub(crate) values: Vec<(String, String)>,
 }
 
 /// Sink object that allows for writing to a [Grafana Loki](https://grafana.com/oss/loki/) implementation
@@ -55,15 +94,42 @@ struct LokiStream {
 /// * Target
 /// * File
 /// * level
+///
+/// Logging never blocks on network I/O: `log` hands each entry to a bounded channel and a
+/// dedicated background worker thread owns batching and sending them to Loki. If Loki is
+/// unreachable, or answers with a rate-limit or server error, the worker retries the batch with
+/// exponential backoff instead of dropping it. A batch is otherwise only dropped if Loki itself
+/// rejects it (e.g. malformed input), if it keeps failing past the retry limit, or if the channel
+/// fills up faster than the worker can drain it.
+///
+/// `promoted_labels` names properties that should be pulled out of the per-log JSON body and
+/// promoted into the per-entry stream labels instead (e.g. `level` or `Environment`), letting
+/// Loki index them for queries like `{level="error"}`. **Only promote low-cardinality
+/// properties** — every distinct value (or combination of values, if promoting more than one
+/// property) becomes its own Loki stream on the server, and a high-cardinality label (a
+/// `CorrelationId`, a user id, ...) can blow up the number of streams Loki has to track.
+///
+/// `directives` holds per-target level overrides consulted by [`Log::enabled`], so framework
+/// chatter from a noisy dependency can be silenced without lowering the crate-wide level passed
+/// to `log::set_max_level`, which still filters every record before the directives see it.
 #[derive(Debug)]
 pub(crate) struct LokiSink {
-	url: String,
 	labels: HashMap<String, String>,
-	buffer: RwLock<Vec<LokiStream>>,
+	promoted_labels: PromotedLabels,
+	directives: LevelDirectives,
+	sender: SyncSender<LokiStream>,
 }
 
 impl LokiSink {
-	pub(crate) fn new(url: impl AsRef<str>, labels: LokiLabels) -> Self {
+	pub(crate) fn new(
+		url: impl AsRef<str>,
+		labels: LokiLabels,
+		promoted_labels: PromotedLabels,
+		directives: LevelDirectives,
+		encoding: PushEncoding,
+		queue_capacity: usize,
+		flush_interval: Duration,
+	) -> Self {
 		let initial_labels = match labels {
 			Some(labels) => {
 				labels
@@ -76,17 +142,180 @@ impl LokiSink {
 			None => HashMap::new()
 		};
 
+		let (sender, receiver) = mpsc::sync_channel(queue_capacity);
+		let url = String::from(url.as_ref());
+
+		thread::spawn(move || run_worker(receiver, url, encoding, flush_interval));
+
 		Self {
-			url: String::from(url.as_ref()),
 			labels: initial_labels,
-			buffer: RwLock::new(Vec::new()),
+			directives,
+			promoted_labels,
+			sender,
 		}
 	}
 }
 
+/// Background worker loop that owns the receiving half of the channel: it accumulates entries
+/// into batches of up to `BATCH_LIMIT`, flushing early once `flush_interval` elapses with nothing
+/// new arriving, and retries a failed push with exponential backoff rather than losing it.
+fn run_worker(receiver: Receiver<LokiStream>, url: String, encoding: PushEncoding, flush_interval: Duration) {
+	let mut batch = Vec::new();
+
+	loop {
+		match receiver.recv_timeout(flush_interval) {
+			Ok(stream) => {
+				batch.push(stream);
+
+				while batch.len() < BATCH_LIMIT {
+					match receiver.try_recv() {
+						Ok(stream) => batch.push(stream),
+						Err(_) => break,
+					}
+				}
+
+				// keep accumulating until the batch is full or the interval passes with nothing new
+				if batch.len() < BATCH_LIMIT {
+					continue;
+				}
+			}
+			// nothing arrived within the interval; flush whatever we already have
+			Err(RecvTimeoutError::Timeout) => {}
+			// the sink (and its sender) has been dropped: send what is left and stop
+			Err(RecvTimeoutError::Disconnected) => {
+				if !batch.is_empty() {
+					send_with_retry(&url, encoding, group_by_labels(batch));
+				}
+				return;
+			}
+		}
+
+		if batch.is_empty() {
+			continue;
+		}
+
+		send_with_retry(&url, encoding, group_by_labels(std::mem::take(&mut batch)));
+	}
+}
+
+/// Groups the buffered entries by their resulting label set so each distinct combination of
+/// labels becomes its own `LokiStream`, as Loki's push API expects, instead of one stream per
+/// log entry
+fn group_by_labels(streams: Vec<LokiStream>) -> Vec<LokiStream> {
+	let mut grouped: HashMap<String, LokiStream> = HashMap::new();
+
+	for stream in streams {
+		let key = crate::proto::format_labels(&stream.stream);
+
+		match grouped.get_mut(&key) {
+			Some(existing) => existing.values.extend(stream.values),
+			None => {
+				grouped.insert(key, stream);
+			}
+		}
+	}
+
+	if grouped.len() > HIGH_CARDINALITY_WARN_THRESHOLD {
+		eprintln!(
+			"loki-sink: {} distinct stream label combinations in one batch; a promoted property may be too high-cardinality for a Loki stream label",
+			grouped.len()
+		);
+	}
+
+	grouped.into_values().collect()
+}
+
+/// Pushes a batch to Loki, retrying transient failures (timeouts, connection errors, `429`, and
+/// `5xx` responses) with an exponential backoff capped at `MAX_RETRY_DELAY`. A `4xx` response
+/// other than `429` means Loki rejected the batch itself (e.g. a malformed or out-of-order
+/// stream) and retrying it verbatim would just fail the same way forever, so it is logged once
+/// and dropped instead. Retries are also bounded by `MAX_SEND_ATTEMPTS` so a batch that keeps
+/// hitting transient errors can't wedge the worker and starve every entry queued behind it.
+fn send_with_retry(url: &str, encoding: PushEncoding, streams: Vec<LokiStream>) {
+	let mut delay = INITIAL_RETRY_DELAY;
+	let entries: usize = streams.iter().map(|stream| stream.values.len()).sum();
+
+	for attempt in 1..=MAX_SEND_ATTEMPTS {
+		match send_batch(url, encoding, &streams) {
+			Ok(()) => return,
+			Err(e) if !is_retryable(&e) => {
+				eprintln!("loki rejected a batch of {} log entries, dropping it: {:?}", entries, e);
+				return;
+			}
+			Err(e) if attempt == MAX_SEND_ATTEMPTS => {
+				eprintln!(
+					"failed to push {} log entries to loki after {} attempts, dropping batch: {:?}",
+					entries,
+					attempt,
+					e
+				);
+				return;
+			}
+			Err(e) => {
+				eprintln!("failed to push {} log entries to loki, retrying in {:?}: {:?}", entries, delay, e);
+				thread::sleep(delay);
+				delay = (delay * 2).min(MAX_RETRY_DELAY);
+			}
+		}
+	}
+}
+
+/// Whether `error` represents a transient failure worth retrying: a transport-level error
+/// (timeout, connection refused, ...), a `429` (rate limited), or a `5xx` server error. Any other
+/// status code means Loki looked at the batch and rejected it, so retrying it unchanged would
+/// only fail the same way again.
+fn is_retryable(error: &ureq::Error) -> bool {
+	match error {
+		ureq::Error::Transport(_) => true,
+		ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+	}
+}
+
+fn send_batch(url: &str, encoding: PushEncoding, streams: &[LokiStream]) -> Result<(), ureq::Error> {
+	match encoding {
+		PushEncoding::Json => {
+			ureq::post(url).send_json(LokiRequest { streams: streams.to_vec() })?;
+		}
+		PushEncoding::ProtobufSnappy => {
+			let encoded = crate::proto::encode_push_request(streams);
+			let compressed = snap::raw::Encoder::new().compress_vec(&encoded).expect("snappy compression failed");
+
+			ureq::post(url).set("Content-Type", "application/x-protobuf").send_bytes(&compressed)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Collects the structured key-values attached to a [`log::Record`] (e.g. via
+/// `info!(user_id = 42, "login")`) into the same `String -> serde_json::Value` shape the
+/// property bag uses, so the two can be merged into one set of per-log properties.
+///
+/// Values are captured via their `Display` representation rather than their native JSON type,
+/// since the `log` crate only exposes typed serialization behind an optional feature; this keeps
+/// the dependency footprint the same as everything else in this crate.
+#[derive(Default)]
+struct KeyValueCollector(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> Visitor<'kvs> for KeyValueCollector {
+	fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+		self.0.push((key.to_string(), serde_json::Value::String(value.to_string())));
+		Ok(())
+	}
+}
+
+/// Renders a property value as a Loki stream label value: strings are used as-is, everything
+/// else falls back to its JSON representation
+fn json_value_to_label(value: &serde_json::Value) -> String {
+	match value {
+		serde_json::Value::String(s) => s.clone(),
+		other => other.to_string(),
+	}
+}
+
 impl Log for LokiSink {
-	fn enabled(&self, _metadata: &log::Metadata) -> bool {
-		true
+	fn enabled(&self, metadata: &log::Metadata) -> bool {
+		self.directives.enabled(metadata.target(), metadata.level())
 	}
 
 	fn log(&self, record: &log::Record) {
@@ -104,47 +333,104 @@ impl Log for LokiSink {
 		prop!("File", &record.file());
 		prop!("level", &record.level().to_string().to_ascii_lowercase());
 
-		let message_json = PROPERTY_BAG.as_json();
+		let mut props = PROPERTY_BAG.snapshot();
+
+		// structured key-values attached at the call site, e.g. `info!(user_id = 42, "login")`.
+		// These are the lowest precedence property source: they fill in fields the property bag
+		// and the standard set above haven't already claimed, but never override them.
+		let mut kv_collector = KeyValueCollector::default();
+		let _ = record.key_values().visit(&mut kv_collector);
+		for (key, value) in kv_collector.0 {
+			props.entry(key).or_insert(value);
+		}
+
+		// promote configured properties out of the line body and into this entry's stream labels
+		let mut stream_labels = self.labels.clone();
+		for name in &self.promoted_labels {
+			if let Some(value) = props.remove(*name) {
+				stream_labels.insert(String::from(*name), json_value_to_label(&value));
+			}
+		}
+
+		let message_json = serde_json::to_string(&props).unwrap();
 		let span = [(time, message_json)].to_vec();
 
 		let req = LokiStream {
-			stream: self.labels.clone(),
+			stream: stream_labels,
 			values: span,
 		};
 
-		self.buffer.write().unwrap().push(req);
-
-		// limit is hit, let's try to flush the logs to the server
-		if self.buffer.read().unwrap().len() >= BATCH_LIMIT {
-			self.flush();
+		// never block the logging thread on the network: hand the entry to the background
+		// worker and drop it if the bounded queue is already full
+		if let Err(TrySendError::Full(_)) = self.sender.try_send(req) {
+			eprintln!("loki log queue is full, dropping log entry");
 		}
 	}
 
 	fn flush(&self) {
-		let mut req = match self.buffer.try_write() {
-			Ok(r) => r,
-			// if we can't get a lock, well just try again next time flush is called
-			Err(_) => return,
-		};
+		// flushing is owned entirely by the background worker's batching loop; there is no
+		// longer a caller-accessible buffer to drain synchronously here
+	}
+}
 
-		let batch_size = req.len().clamp(0, BATCH_LIMIT);
-		if batch_size == 0 {
-			return;
-		}
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-		let payload = LokiRequest {
-			streams: (*req).drain(..batch_size).collect(),
-		};
+	fn status_error(code: u16) -> ureq::Error {
+		ureq::Error::Status(code, ureq::Response::new(code, "status", "body").unwrap())
+	}
 
-		// we are done with the RwLock. drop it so that any logging in ureq and its dependencies
-		// will not cause deadlocks
-		drop(req);
+	#[test]
+	fn transport_errors_are_retryable() {
+		let err = ureq::get("http://127.0.0.1:0").call().unwrap_err();
+		assert!(matches!(err, ureq::Error::Transport(_)));
+		assert!(is_retryable(&err));
+	}
 
-		// for now just swallow and print to stderr
-		// this can sometimes cause things to write to stderr if the program/thread execution
-		// stops in the middle of making the call
-		if let Err(e) = ureq::post(&self.url).send_json(payload) {
-			eprintln!("{:?}", e);
+	#[test]
+	fn rate_limit_and_server_errors_are_retryable() {
+		assert!(is_retryable(&status_error(429)));
+		assert!(is_retryable(&status_error(500)));
+		assert!(is_retryable(&status_error(503)));
+	}
+
+	#[test]
+	fn other_client_errors_are_not_retryable() {
+		assert!(!is_retryable(&status_error(400)));
+		assert!(!is_retryable(&status_error(404)));
+	}
+
+	fn stream(labels: &[(&str, &str)], values: &[(&str, &str)]) -> LokiStream {
+		LokiStream {
+			stream: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+			values: values.iter().map(|(t, l)| (t.to_string(), l.to_string())).collect(),
 		}
 	}
+
+	#[test]
+	fn group_by_labels_merges_entries_with_matching_labels() {
+		let streams = vec![
+			stream(&[("level", "info")], &[("1", "a")]),
+			stream(&[("level", "error")], &[("2", "b")]),
+			stream(&[("level", "info")], &[("3", "c")]),
+		];
+
+		let mut grouped = group_by_labels(streams);
+		grouped.sort_by(|a, b| a.stream.get("level").cmp(&b.stream.get("level")));
+
+		assert_eq!(grouped.len(), 2);
+		assert_eq!(grouped[0].stream.get("level").map(String::as_str), Some("error"));
+		assert_eq!(grouped[0].values, vec![("2".to_string(), "b".to_string())]);
+		assert_eq!(grouped[1].stream.get("level").map(String::as_str), Some("info"));
+		assert_eq!(grouped[1].values, vec![("1".to_string(), "a".to_string()), ("3".to_string(), "c".to_string())]);
+	}
+
+	#[test]
+	fn group_by_labels_keeps_distinct_label_sets_separate() {
+		let streams = vec![stream(&[("level", "info")], &[("1", "a")]), stream(&[("level", "info"), ("env", "stage")], &[("2", "b")])];
+
+		let grouped = group_by_labels(streams);
+		assert_eq!(grouped.len(), 2);
+	}
 }